@@ -1,14 +1,65 @@
+import argparse
+import base64
+import hashlib
 import json
 import os
+import platform
 import random
+import sys
 import time
+import urllib.error
 import urllib.request
 
-from datetime import datetime
+from datetime import date, datetime
+from email.utils import parsedate_to_datetime
 
 
-noaa = "https://api.weather.gov/gridpoints/LWX/97,75/forecast/hourly"
-pushover = "https://api.pushover.net/1/messages.json"
+location = "LWX/97,75"
+# override for proxies, mirrors, air-gapped deployments or local test stubs
+nws_base = os.environ.get("NWS_BASE_URL", "https://api.weather.gov").rstrip("/")
+pushover_base = os.environ.get(
+    "PUSHOVER_BASE_URL", "https://api.pushover.net"
+).rstrip("/")
+noaa = f"{nws_base}/gridpoints/{location}/forecast/hourly"
+pushover = f"{pushover_base}/1/messages.json"
+
+# optional JSON file remembering what was already sent between runs
+state_file = os.environ.get("STATE_FILE")
+
+# also report drizzly-but-rideable windows in their own section
+rain_gear = os.environ.get("RAIN_GEAR") == "1"
+light_rain = ["drizzle", "light rain", "sprinkles"]
+
+# where viewer.html is hosted, required for --output share-url
+viewer = os.environ.get("SHARE_VIEWER_URL")
+
+# seconds between forecast checks in daemon mode, checked in __main__
+poll_interval = os.environ.get("POLL_INTERVAL", "3600")
+
+
+def parse_priorities(raw):
+    priorities = {}
+    for pair in raw.split(","):
+        if not pair.strip():
+            continue
+        g, _, p = pair.partition(":")
+        try:
+            priorities[g.strip()] = int(p)
+        except ValueError:
+            msg = f"ignoring PUSHOVER_PRIORITIES entry {pair!r}, expected GRADE:N"
+            print(msg, file=sys.stderr)
+    return priorities
+
+
+# pushover priority per window grade, lowered the further off the window is;
+# emergency (2) needs acknowledgement so it's capped at high (1)
+pushover_priorities = parse_priorities(
+    os.environ.get("PUSHOVER_PRIORITIES", "A:1,B:0,C:-1")
+)
+
+# unset lists times only; "imperial" adds temperature and wind, "dual" shows
+# both unit systems for mixed groups
+units = os.environ.get("UNITS")
 
 
 def fmt_date(d):
@@ -19,6 +70,93 @@ def fmt_time(d):
     return datetime.fromisoformat(d).strftime("%I:%M %p")
 
 
+def wind_mph(p):
+    # si requests report e.g. "16 km/h" alongside Celsius temperatures
+    speed = int(p["windSpeed"].split(" ")[0])
+    if p["windSpeed"].endswith("km/h"):
+        return round(speed / 1.609)
+    return speed
+
+
+def temp_f(p):
+    if p.get("temperatureUnit") == "C":
+        return round(p["temperature"] * 9 / 5 + 32)
+    return p["temperature"]
+
+
+def fmt_temp(f):
+    if units == "dual":
+        return f"{f}F / {round((f - 32) * 5 / 9)}C"
+    return f"{f}F"
+
+
+def fmt_wind(mph):
+    if units == "dual":
+        return f"{mph} mph / {round(mph * 1.609)} km/h"
+    return f"{mph} mph"
+
+
+def fmt_block(b):
+    if not units:
+        return f"{fmt_date(b['startTime'])} to {fmt_time(b['endTime'])}"
+    return (
+        f"{fmt_date(b['startTime'])} to {fmt_time(b['endTime'])}, "
+        f"{fmt_temp(b['temperature'])}, wind {fmt_wind(b['wind'])}"
+    )
+
+
+def overlaps(b, w):
+    start, end = (datetime.fromisoformat(b[k]) for k in ("startTime", "endTime"))
+    return (
+        datetime.fromisoformat(w["startTime"]) < end
+        and start < datetime.fromisoformat(w["endTime"])
+    )
+
+
+def hash_id(b):
+    # same place, day and starting hour give the same id
+    start = datetime.fromisoformat(b["startTime"]).replace(minute=0, second=0)
+    key = f"{location}|{start.date().isoformat()}|{start.hour:02d}"
+    return hashlib.sha1(key.encode()).hexdigest()[:12]
+
+
+def sent_match(b, sent):
+    # look up by id first. past hours drop off the forecast, so a window
+    # already under way starts later on every run and hashes differently;
+    # fall back to overlap so it carries its original id forward
+    wid = hash_id(b)
+    by_id = next((w for w in sent if w["id"] == wid), None)
+    return by_id or next((w for w in sent if overlaps(b, w)), None)
+
+
+def window_id(b, sent=()):
+    match = sent_match(b, sent)
+    return match["id"] if match else hash_id(b)
+
+
+def is_fresh(b, sent):
+    # a window that now runs later than when it was sent is worth a new message
+    match = sent_match(b, sent)
+    if match is None:
+        return True
+    end = datetime.fromisoformat(b["endTime"])
+    return end > datetime.fromisoformat(match["endTime"])
+
+
+def load_state():
+    if not state_file or not os.path.exists(state_file):
+        return {}
+    with open(state_file) as f:
+        return json.load(f)
+
+
+def save_state(state):
+    if not state_file:
+        return
+    with open(state_file, "w") as f:
+        json.dump(state, f)
+
+
 def retry(fn, n):
     for i in range(n):
         try:
@@ -30,37 +168,141 @@ def retry(fn, n):
             time.sleep(backoff)
 
 
-def run():
-    with urllib.request.urlopen(noaa) as response:
-        data = json.loads(response.read().decode())
+def normalize(p):
+    # only the fields the filters look at, so cached hours compare cleanly.
+    # the api renumbers periods from the current hour, so "number" is left out
+    return {
+        "startTime": p["startTime"],
+        "endTime": p["endTime"],
+        "isDaytime": p["isDaytime"],
+        "temperature": temp_f(p),
+        "precip": p["probabilityOfPrecipitation"]["value"],
+        "wind": wind_mph(p),
+        "shortForecast": p["shortForecast"],
+    }
+
+
+def is_temperate(p):
+    return (
+        p["isDaytime"] and p["precip"] < 25 and p["temperature"] > 50 and p["wind"] < 13
+    )
 
-    temperate = [
-        p
-        for p in data["properties"]["periods"]
-        if p["isDaytime"]
-        and p["probabilityOfPrecipitation"]["value"] < 25
+
+def is_rain_gear(p):
+    # drizzle keywords count even at low precip, so those hours move out of
+    # the good windows when the rain gear section is on
+    drizzly = any(k in p["shortForecast"].lower() for k in light_rain)
+    return (
+        p["isDaytime"]
+        and (25 <= p["precip"] <= 50 or (drizzly and p["precip"] <= 50))
         and p["temperature"] > 50
-        and int(p["windSpeed"].split(" ")[0]) < 13
-    ]
+        and p["wind"] < 13
+    )
 
+
+def coalesce(periods):
     blocks = []
-    num = temperate[0]["number"] if len(temperate) == 0 else 0
 
-    for period in temperate:
-        if period["number"] == num + 1 and len(blocks) > 0:
+    for period in periods:
+        if blocks and period["startTime"] == blocks[-1]["endTime"]:
             last = blocks.pop()
             last["endTime"] = period["endTime"]
+            last["temperature"] = max(last["temperature"], period["temperature"])
+            last["wind"] = max(last["wind"], period["wind"])
+            last["precip"] = max(last["precip"], period["precip"])
             blocks.append(last)
         else:
-            blocks.append(period)
-        num = period["number"]
+            blocks.append(dict(period))
 
-    schedule = "\n".join(
-        [f"{fmt_date(b['startTime'])} to {fmt_time(b['endTime'])}" for b in blocks]
-    )
+    return blocks
 
-    msg = f"bike times 🚲\n{schedule}"
-    print(msg)
+
+def evaluate_day(periods):
+    if not rain_gear:
+        return coalesce([p for p in periods if is_temperate(p)]), []
+    blocks = coalesce([p for p in periods if is_temperate(p) and not is_rain_gear(p)])
+    rainy = coalesce([p for p in periods if is_rain_gear(p)])
+    return blocks, rainy
+
+
+def evaluate(periods, cache=None):
+    # windows never span midnight, so each day can be evaluated on its own.
+    # with a cache, days whose hours match the previous poll are reused.
+    days = {}
+    for p in periods:
+        days.setdefault(p["startTime"][:10], []).append(p)
+
+    if cache is None:
+        cache = {}
+    for day in list(cache):
+        if day not in days:
+            del cache[day]
+
+    blocks, rainy = [], []
+    for day, hours in days.items():
+        if day not in cache or cache[day][0] != hours:
+            cache[day] = (hours, evaluate_day(hours))
+        day_blocks, day_rainy = cache[day][1]
+        blocks += day_blocks
+        rainy += day_rainy
+
+    return blocks, rainy
+
+
+def fetch():
+    with urllib.request.urlopen(noaa) as response:
+        data = json.loads(response.read().decode())
+    return [normalize(p) for p in data["properties"]["periods"]]
+
+
+def grade(b, rainy=False):
+    if rainy:
+        return "C"
+    if 60 <= b["temperature"] <= 80 and b["wind"] < 8 and b["precip"] < 10:
+        return "A"
+    return "B"
+
+
+def priority(b, rainy=False):
+    days_out = (datetime.fromisoformat(b["startTime"]).date() - date.today()).days
+    decay = 0 if days_out <= 1 else 1 if days_out <= 3 else 2
+    return max(-2, min(1, pushover_priorities.get(grade(b, rainy), 0) - decay))
+
+
+def share_url(blocks, rainy):
+    def compact(b, **extra):
+        return {
+            "s": b["startTime"],
+            "e": b["endTime"],
+            "t": b["temperature"],
+            "w": b["wind"],
+            **extra,
+        }
+
+    windows = [compact(b) for b in blocks] + [compact(b, r=1) for b in rainy]
+    # the viewer formats units the same way the message does
+    payload = json.dumps({"u": units, "w": windows}, separators=(",", ":")).encode()
+    return f"{viewer}#{base64.urlsafe_b64encode(payload).decode().rstrip('=')}"
+
+
+def retry_after(e):
+    header = e.headers.get("Retry-After") if e.headers else None
+    if not header:
+        # pushover sends no hint when the monthly limit is hit
+        return 3600
+    if header.isdigit():
+        return int(header)
+    try:
+        return max(0, parsedate_to_datetime(header).timestamp() - time.time())
+    except (TypeError, ValueError):
+        return 3600
+
+
+def send_pushover(msg, state, level=0):
+    until = state.get("pushover_backoff_until", 0)
+    if until > time.time():
+        print(f"pushover rate limited until {datetime.fromtimestamp(until)}")
+        return False
 
     req = urllib.request.Request(
         pushover,
@@ -69,13 +311,115 @@ def run():
                 "token": os.environ.get("PUSHOVER_TOKEN"),
                 "user": os.environ.get("PUSHOVER_USER"),
                 "message": msg,
+                "priority": level,
             }
         ).encode("utf-8"),
         headers={"content-type": "application/json"},
         method="POST",
     )
-    with urllib.request.urlopen(req) as response:
-        print("message sent")
+    try:
+        with urllib.request.urlopen(req) as response:
+            print("message sent")
+    except urllib.error.HTTPError as e:
+        if e.code != 429:
+            raise
+        # remember the backoff so the next cron run doesn't hammer the api
+        wait = retry_after(e)
+        state["pushover_backoff_until"] = time.time() + wait
+        save_state(state)
+        print(f"pushover rate limited, backing off {wait:.0f}s")
+        return False
 
+    state.pop("pushover_backoff_until", None)
+    return True
+
+
+def info():
+    print(f"location: {location}")
+    print(f"nws: {noaa}")
+    print(f"pushover: {pushover}")
+    print(f"pushover priorities: {pushover_priorities}")
+    print(f"units: {units or 'times only'}")
+    print(f"rain gear: {'enabled' if rain_gear else 'disabled'}")
+    print(f"share viewer: {viewer or 'disabled'}")
+    print(f"poll interval: {poll_interval}s")
+    print(f"state file: {state_file or 'disabled'}")
+    print(f"python: {platform.python_version()}")
+    print(f"target: {sys.platform}-{platform.machine()}")
+
+
+def report(blocks, rainy, output, state):
+    if output == "share-url":
+        print(share_url(blocks, rainy))
+        return
+
+    sent = state.get("sent", [])
+    fresh = [b for b in blocks + rainy if is_fresh(b, sent)]
+    if "sent" in state and not fresh:
+        print("no new bike times")
+        return
+
+    schedule = "\n".join([fmt_block(b) for b in blocks])
+
+    msg = f"bike times 🚲\n{schedule}"
+    if rainy:
+        msg += "\n\nwith rain gear 🧥\n" + "\n".join([fmt_block(b) for b in rainy])
+    print(msg)
+
+    # urgency follows the best window that hasn't been sent yet
+    priorities = [priority(b) for b in blocks if is_fresh(b, sent)] + [
+        priority(b, rainy=True) for b in rainy if is_fresh(b, sent)
+    ]
+    if not send_pushover(msg, state, max(priorities, default=-2)):
+        return
+
+    state["sent"] = [
+        {"id": window_id(b, sent), "startTime": b["startTime"], "endTime": b["endTime"]}
+        for b in blocks + rainy
+    ]
+    save_state(state)
+
+
+def run(output="pushover"):
+    blocks, rainy = evaluate(fetch())
+    report(blocks, rainy, output, load_state())
+
+
+def daemon(output="pushover"):
+    # state stays in memory between polls so repeats are skipped even
+    # without a state file
+    cache = {}
+    state = load_state()
+
+    def poll():
+        blocks, rainy = evaluate(fetch(), cache)
+        report(blocks, rainy, output, state)
+
+    while True:
+        retry(poll, 3)
+        time.sleep(poll_interval)
+
+
+if __name__ == "__main__":
+    parser = argparse.ArgumentParser(description="tells me when to go biking")
+    parser.add_argument(
+        "command", nargs="?", choices=["run", "daemon", "info"], default="run"
+    )
+    parser.add_argument(
+        "--output", choices=["pushover", "share-url"], default="pushover"
+    )
+    args = parser.parse_args()
+    if args.output == "share-url" and not viewer:
+        parser.error("--output share-url needs SHARE_VIEWER_URL")
+    if units not in (None, "imperial", "dual"):
+        parser.error(f"UNITS must be imperial or dual, not {units!r}")
+    if not poll_interval.isdigit():
+        parser.error(f"POLL_INTERVAL must be whole seconds, not {poll_interval!r}")
+    poll_interval = int(poll_interval)
 
-retry(run, 3)
+    if args.command == "info":
+        info()
+    elif args.command == "daemon":
+        daemon(args.output)
+    else:
+        retry(lambda: run(args.output), 3)
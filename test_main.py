@@ -0,0 +1,300 @@
+import importlib.util
+import json
+import os
+import threading
+import unittest
+from datetime import date, timedelta
+from http.server import BaseHTTPRequestHandler, HTTPServer
+from unittest import mock
+
+import main
+
+
+def hour(start, **fields):
+    p = {
+        "startTime": f"2024-06-01T{start:02d}:00:00-04:00",
+        "endTime": f"2024-06-01T{start + 1:02d}:00:00-04:00",
+        "isDaytime": True,
+        "temperature": 64,
+        "precip": 5,
+        "wind": 5,
+        "shortForecast": "Sunny",
+    }
+    return {**p, **fields}
+
+
+def api_hour(temperature, unit, wind):
+    return {
+        "number": 1,
+        "startTime": "2024-06-01T09:00:00-04:00",
+        "endTime": "2024-06-01T10:00:00-04:00",
+        "isDaytime": True,
+        "temperature": temperature,
+        "temperatureUnit": unit,
+        "probabilityOfPrecipitation": {"unitCode": "wmoUnit:percent", "value": 5},
+        "windSpeed": wind,
+        "shortForecast": "Sunny",
+    }
+
+
+class TestNormalize(unittest.TestCase):
+    def test_fahrenheit_mph(self):
+        p = main.normalize(api_hour(64, "F", "10 mph"))
+        self.assertEqual((p["temperature"], p["wind"]), (64, 10))
+        self.assertTrue(main.is_temperate(p))
+
+    def test_celsius_kmh(self):
+        p = main.normalize(api_hour(18, "C", "16 km/h"))
+        self.assertEqual((p["temperature"], p["wind"]), (64, 10))
+        self.assertTrue(main.is_temperate(p))
+
+    def test_units_agree(self):
+        imperial = main.normalize(api_hour(46, "F", "14 mph"))
+        si = main.normalize(api_hour(8, "C", "22 km/h"))
+        self.assertEqual(imperial, si)
+        self.assertFalse(main.is_temperate(si))
+
+
+class TestFormat(unittest.TestCase):
+    def test_default_lists_times_only(self):
+        with mock.patch.object(main, "units", None):
+            self.assertEqual(main.fmt_block(hour(9)), "Sat 09:00 AM to 10:00 AM")
+
+    def test_dual_units(self):
+        with mock.patch.object(main, "units", "dual"):
+            self.assertEqual(
+                main.fmt_block(hour(9, wind=10)),
+                "Sat 09:00 AM to 10:00 AM, 64F / 18C, wind 10 mph / 16 km/h",
+            )
+
+
+class TestPriorities(unittest.TestCase):
+    def test_parse_skips_empty_and_bad_entries(self):
+        with mock.patch("sys.stderr"):
+            parsed = main.parse_priorities("A:1,B:0,,C:x,")
+        self.assertEqual(parsed, {"A": 1, "B": 0})
+
+    def test_grade_and_imminence(self):
+        tomorrow = date.today() + timedelta(days=1)
+        later = date.today() + timedelta(days=6)
+        a = hour(9, temperature=70, startTime=f"{tomorrow}T09:00:00")
+        c = hour(9, startTime=f"{later}T09:00:00")
+        self.assertEqual(main.priority(a), 1)
+        self.assertEqual(main.priority(c, rainy=True), -2)
+
+
+class TestShareUrl(unittest.TestCase):
+    def test_payload_carries_units(self):
+        with mock.patch.object(main, "viewer", "http://viewer"):
+            with mock.patch.object(main, "units", "dual"):
+                url = main.share_url([hour(9)], [hour(10)])
+
+        base, fragment = url.split("#")
+        padded = fragment + "=" * (-len(fragment) % 4)
+        data = main.json.loads(main.base64.urlsafe_b64decode(padded))
+        self.assertEqual(base, "http://viewer")
+        self.assertEqual(data["u"], "dual")
+        self.assertEqual([w.get("r") for w in data["w"]], [None, 1])
+
+
+class TestRainGear(unittest.TestCase):
+    def setUp(self):
+        drizzle = hour(10, precip=15, shortForecast="Light Rain")
+        self.day = [hour(9), drizzle, hour(11, precip=40), hour(12)]
+
+    def test_drizzle_at_low_precip_is_rain_gear(self):
+        with mock.patch.object(main, "rain_gear", True):
+            blocks, rainy = main.evaluate_day(self.day)
+
+        self.assertEqual([b["startTime"][11:13] for b in blocks], ["09", "12"])
+        self.assertEqual([b["startTime"][11:13] for b in rainy], ["10"])
+        self.assertEqual(rainy[0]["endTime"], "2024-06-01T12:00:00-04:00")
+
+    def test_default_keeps_strict_filter(self):
+        with mock.patch.object(main, "rain_gear", False):
+            blocks, rainy = main.evaluate_day(self.day)
+
+        self.assertEqual([b["startTime"][11:13] for b in blocks], ["09", "12"])
+        self.assertEqual(blocks[0]["endTime"], "2024-06-01T11:00:00-04:00")
+        self.assertEqual(rainy, [])
+
+
+class TestEvaluateCache(unittest.TestCase):
+    def poll(self, hours):
+        # the api numbers hourly periods from 1 starting at the current hour
+        periods = []
+        for n, (day, start) in enumerate(hours, 1):
+            p = api_hour(64, "F", "5 mph")
+            p["number"] = n
+            p["startTime"] = f"2024-06-0{day}T{start:02d}:00:00-04:00"
+            p["endTime"] = f"2024-06-0{day}T{start + 1:02d}:00:00-04:00"
+            periods.append(p)
+        return [main.normalize(p) for p in periods]
+
+    def setUp(self):
+        self.days = []
+        evaluate_day = main.evaluate_day
+
+        def counted(hours):
+            self.days.append(hours[0]["startTime"][:10])
+            return evaluate_day(hours)
+
+        patcher = mock.patch.object(main, "evaluate_day", counted)
+        patcher.start()
+        self.addCleanup(patcher.stop)
+
+    def test_unchanged_day_is_reused_after_renumbering(self):
+        cache = {}
+        first = [(1, h) for h in range(9, 15)] + [(2, h) for h in range(9, 15)]
+        main.evaluate(self.poll(first), cache)
+        self.assertEqual(self.days, ["2024-06-01", "2024-06-02"])
+
+        # an hour later the 9am hour is gone and every period is renumbered
+        self.days.clear()
+        blocks, _ = main.evaluate(self.poll(first[1:]), cache)
+
+        self.assertEqual(self.days, ["2024-06-01"])
+        self.assertEqual(blocks[0]["startTime"], "2024-06-01T10:00:00-04:00")
+        self.assertEqual(blocks[1]["startTime"], "2024-06-02T09:00:00-04:00")
+
+    def test_days_that_left_the_forecast_are_dropped(self):
+        cache = {}
+        main.evaluate(self.poll([(1, 9), (2, 9)]), cache)
+        main.evaluate(self.poll([(2, 9)]), cache)
+
+        self.assertEqual(list(cache), ["2024-06-02"])
+
+
+class TestDedup(unittest.TestCase):
+    def setUp(self):
+        patcher = mock.patch.object(main, "send_pushover", return_value=True)
+        self.send = patcher.start()
+        self.addCleanup(patcher.stop)
+        quiet = mock.patch("builtins.print")
+        quiet.start()
+        self.addCleanup(quiet.stop)
+
+    def test_window_under_way_keeps_its_id(self):
+        state = {}
+        morning = {**hour(10), "endTime": "2024-06-01T15:00:00-04:00"}
+        main.report([morning], [], "pushover", state)
+        first = state["sent"][0]["id"]
+
+        # an hour later the 10am hour has dropped off the forecast
+        later = {**hour(11), "endTime": "2024-06-01T15:00:00-04:00"}
+        main.report([later], [], "pushover", state)
+
+        self.assertEqual(self.send.call_count, 1)
+        self.assertEqual(main.window_id(later, state["sent"]), first)
+
+    def test_same_window_is_found_by_id(self):
+        state = {}
+        main.report([hour(9)], [], "pushover", state)
+        state["sent"][0]["startTime"] = "2024-06-01T06:00:00-04:00"
+        state["sent"][0]["endTime"] = "2024-06-01T12:00:00-04:00"
+        state["sent"].insert(0, {**state["sent"][0], "id": "overlapping"})
+
+        # both entries overlap, the id match wins over the earlier one
+        main.report([hour(9)], [], "pushover", state)
+
+        self.assertEqual(self.send.call_count, 1)
+        wid = main.window_id(hour(9), state["sent"])
+        self.assertEqual(wid, main.hash_id(hour(9)))
+
+    def test_window_that_grows_is_sent_again(self):
+        state = {}
+        short = {**hour(9), "endTime": "2024-06-01T11:00:00-04:00"}
+        main.report([short], [], "pushover", state)
+        first = state["sent"][0]["id"]
+
+        longer = {**hour(9), "endTime": "2024-06-01T17:00:00-04:00"}
+        main.report([longer], [], "pushover", state)
+
+        self.assertEqual(self.send.call_count, 2)
+        self.assertEqual(state["sent"][0]["id"], first)
+        self.assertEqual(state["sent"][0]["endTime"], longer["endTime"])
+
+    def test_new_window_is_sent(self):
+        state = {}
+        main.report([hour(9)], [], "pushover", state)
+        main.report([hour(9), hour(13)], [], "pushover", state)
+
+        self.assertEqual(self.send.call_count, 2)
+        self.assertEqual(len(state["sent"]), 2)
+
+
+class TestRetryAfter(unittest.TestCase):
+    def error(self, headers):
+        return main.urllib.error.HTTPError(main.pushover, 429, "", headers, None)
+
+    def test_seconds(self):
+        self.assertEqual(main.retry_after(self.error({"Retry-After": "120"})), 120)
+
+    def test_missing_or_malformed_falls_back(self):
+        self.assertEqual(main.retry_after(self.error({})), 3600)
+        self.assertEqual(main.retry_after(self.error({"Retry-After": "soon"})), 3600)
+
+    def test_backoff_is_saved(self):
+        state = {}
+        err = self.error({"Retry-After": "not a date"})
+        with mock.patch.object(main.urllib.request, "urlopen", side_effect=err):
+            with mock.patch("builtins.print"):
+                self.assertFalse(main.send_pushover("hi", state))
+        self.assertGreater(state["pushover_backoff_until"], main.time.time() + 3000)
+
+
+class Stub(BaseHTTPRequestHandler):
+    forecast = {"properties": {"periods": [api_hour(64, "F", "5 mph")]}}
+
+    def do_GET(self):
+        self.reply(self.forecast)
+
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers["content-length"]))
+        self.server.posts.append((self.path, json.loads(body)))
+        self.reply({"status": 1})
+
+    def reply(self, data):
+        self.send_response(200)
+        self.end_headers()
+        self.wfile.write(json.dumps(data).encode())
+
+    def log_message(self, *args):
+        pass
+
+
+class TestStubEndpoints(unittest.TestCase):
+    def setUp(self):
+        self.server = HTTPServer(("127.0.0.1", 0), Stub)
+        self.server.posts = []
+        threading.Thread(target=self.server.serve_forever, daemon=True).start()
+        self.addCleanup(self.server.server_close)
+        self.addCleanup(self.server.shutdown)
+        self.base = f"http://127.0.0.1:{self.server.server_port}/"
+
+    def load_with_env(self):
+        env = {"NWS_BASE_URL": self.base, "PUSHOVER_BASE_URL": self.base}
+        with mock.patch.dict(os.environ, env):
+            spec = importlib.util.spec_from_file_location("stubbed", main.__file__)
+            stubbed = importlib.util.module_from_spec(spec)
+            spec.loader.exec_module(stubbed)
+        return stubbed
+
+    def test_env_overrides_base_urls(self):
+        stubbed = self.load_with_env()
+        forecast = f"{self.base}gridpoints/LWX/97,75/forecast/hourly"
+        self.assertEqual(stubbed.noaa, forecast)
+        self.assertEqual(stubbed.pushover, f"{self.base}1/messages.json")
+
+    def test_run_against_stub(self):
+        stubbed = self.load_with_env()
+        with mock.patch("builtins.print"):
+            stubbed.run()
+
+        [(path, body)] = self.server.posts
+        self.assertEqual(path, "/1/messages.json")
+        self.assertEqual(body["message"], "bike times 🚲\nSat 09:00 AM to 10:00 AM")
+
+
+if __name__ == "__main__":
+    unittest.main()